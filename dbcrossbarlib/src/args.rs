@@ -4,6 +4,15 @@ use bitflags::bitflags;
 use std::marker::PhantomData;
 
 use crate::common::*;
+use crate::migrations::Migrations;
+use crate::predicate::Predicate;
+
+/// Build an error aggregating every unsupported argument found by a
+/// `verify()` method, instead of bailing out on the first mismatch, so users
+/// see everything they need to fix in one message.
+fn unsupported_arguments_error(kind: &str, unsupported: &[String]) -> Error {
+    format_err!("{} does not support: {}", kind, unsupported.join("; "))
+}
 
 /// This is a marker trait used by `SharedArguments`, `SourceArguments` and
 /// `DestinationArguments`. We use it to keep track whether or not the arguments
@@ -90,7 +99,21 @@ bitflags! {
     /// What `SourceArguments` features are supported by a given driver?
     pub struct SourceArgumentsFeatures: u8 {
         const DRIVER_ARGS = 0b0000_0001;
-        const WHERE_CLAUSE = 0b0000_0010;
+        const PREDICATE = 0b0000_0010;
+    }
+}
+
+impl SourceArgumentsFeatures {
+    /// The names of the flags set here, e.g. for a `FeatureReport`.
+    pub(crate) fn names(self) -> Vec<&'static str> {
+        let mut names = vec![];
+        if self.contains(SourceArgumentsFeatures::DRIVER_ARGS) {
+            names.push("driver_args");
+        }
+        if self.contains(SourceArgumentsFeatures::PREDICATE) {
+            names.push("predicate");
+        }
+        names
     }
 }
 
@@ -100,8 +123,9 @@ pub struct SourceArguments<ArgumentState> {
     /// Driver-specific arguments for our data source.
     driver_args: DriverArguments,
 
-    /// A `WHERE` clause for this query.
-    where_clause: Option<String>,
+    /// A portable filter predicate for this query, pushed down by each
+    /// driver into its own dialect. See the `predicate` module for details.
+    predicate: Option<Predicate>,
 
     /// We need to include a reference to `ArgumentState` somewhere, so use a
     /// 0-byte phantom value.
@@ -111,10 +135,10 @@ pub struct SourceArguments<ArgumentState> {
 // These methods are only available in the `Unverified` state.
 impl SourceArguments<Unverified> {
     /// Construct a new `SourceArguments`.
-    pub fn new(driver_args: DriverArguments, where_clause: Option<String>) -> Self {
+    pub fn new(driver_args: DriverArguments, predicate: Option<Predicate>) -> Self {
         Self {
             driver_args,
-            where_clause,
+            predicate,
             _phantom: PhantomData,
         }
     }
@@ -131,23 +155,30 @@ impl SourceArguments<Unverified> {
     ///
     /// [type state]: http://cliffle.com/blog/rust-typestate/
     pub fn verify(self, features: Features) -> Result<SourceArguments<Verified>> {
+        let mut unsupported = vec![];
         if !features
             .source_args
             .contains(SourceArgumentsFeatures::DRIVER_ARGS)
             && !self.driver_args.is_empty()
         {
-            return Err(format_err!("this data source does not support --from-args"));
+            unsupported.push("--from-args".to_owned());
         }
         if !features
             .source_args
-            .contains(SourceArgumentsFeatures::WHERE_CLAUSE)
-            && self.where_clause.is_some()
+            .contains(SourceArgumentsFeatures::PREDICATE)
+            && self.predicate.is_some()
         {
-            return Err(format_err!("this data source does not support --where"));
+            unsupported.push("--where".to_owned());
+        }
+        if !unsupported.is_empty() {
+            return Err(unsupported_arguments_error(
+                "this data source",
+                &unsupported,
+            ));
         }
         Ok(SourceArguments {
             driver_args: self.driver_args,
-            where_clause: self.where_clause,
+            predicate: self.predicate,
             _phantom: PhantomData,
         })
     }
@@ -160,9 +191,84 @@ impl SourceArguments<Verified> {
         &self.driver_args
     }
 
-    /// A `WHERE` clause for this query.
-    pub fn where_clause(&self) -> Option<&str> {
-        self.where_clause.as_ref().map(|s| &s[..])
+    /// A portable filter predicate for this query.
+    pub fn predicate(&self) -> Option<&Predicate> {
+        self.predicate.as_ref()
+    }
+}
+
+bitflags! {
+    /// What `IfExists` behaviors a driver supports, either as a destination
+    /// (via `DestinationArguments::if_exists`) or when writing a schema
+    /// (`Features::write_schema_if_exists`).
+    pub struct IfExistsFeatures: u8 {
+        const ERROR = 0b0000_0001;
+        const APPEND = 0b0000_0010;
+        const OVERWRITE = 0b0000_0100;
+        const UPSERT = 0b0000_1000;
+    }
+}
+
+impl IfExistsFeatures {
+    /// The names of the flags set here, e.g. for a `FeatureReport`.
+    pub(crate) fn names(self) -> Vec<&'static str> {
+        let mut names = vec![];
+        if self.contains(IfExistsFeatures::ERROR) {
+            names.push("error");
+        }
+        if self.contains(IfExistsFeatures::APPEND) {
+            names.push("append");
+        }
+        if self.contains(IfExistsFeatures::OVERWRITE) {
+            names.push("overwrite");
+        }
+        if self.contains(IfExistsFeatures::UPSERT) {
+            names.push("upsert");
+        }
+        names
+    }
+}
+
+/// What should we do if the destination (or schema) we're writing to
+/// already exists?
+#[derive(Clone, Debug, PartialEq)]
+pub enum IfExists {
+    /// Return an error if the destination already exists.
+    Error,
+    /// Append to the destination if it already exists.
+    Append,
+    /// Overwrite the destination if it already exists.
+    Overwrite,
+    /// Merge incoming rows into the destination, matching on `keys`: update
+    /// rows that already exist, and insert the rest. See
+    /// `BqTable::write_merge_sql` for the BigQuery implementation.
+    Upsert { keys: Vec<String> },
+}
+
+impl Default for IfExists {
+    fn default() -> Self {
+        IfExists::Error
+    }
+}
+
+impl IfExists {
+    /// Verify that `features` supports this `IfExists` mode.
+    pub fn verify(&self, features: IfExistsFeatures) -> Result<()> {
+        let supported = match self {
+            IfExists::Error => features.contains(IfExistsFeatures::ERROR),
+            IfExists::Append => features.contains(IfExistsFeatures::APPEND),
+            IfExists::Overwrite => features.contains(IfExistsFeatures::OVERWRITE),
+            IfExists::Upsert { .. } => features.contains(IfExistsFeatures::UPSERT),
+        };
+        if supported {
+            Ok(())
+        } else {
+            Err(format_err!(
+                "this driver does not support {:?} (supported: {:?})",
+                self,
+                features.names(),
+            ))
+        }
     }
 }
 
@@ -170,6 +276,21 @@ bitflags! {
     /// What `DestinationArguments` features are supported by a given driver?
     pub struct DestinationArgumentsFeatures: u8 {
         const DRIVER_ARGS = 0b0000_0001;
+        const MIGRATIONS = 0b0000_0010;
+    }
+}
+
+impl DestinationArgumentsFeatures {
+    /// The names of the flags set here, e.g. for a `FeatureReport`.
+    pub(crate) fn names(self) -> Vec<&'static str> {
+        let mut names = vec![];
+        if self.contains(DestinationArgumentsFeatures::DRIVER_ARGS) {
+            names.push("driver_args");
+        }
+        if self.contains(DestinationArgumentsFeatures::MIGRATIONS) {
+            names.push("migrations");
+        }
+        names
     }
 }
 
@@ -182,6 +303,10 @@ pub struct DestinationArguments<ArgumentState> {
     /// What to do it the destination already exists.
     if_exists: IfExists,
 
+    /// Schema migrations to bring the destination up to date before we
+    /// write any data.
+    migrations: Option<Migrations>,
+
     /// We need to include a reference to `ArgumentState` somewhere, so use a
     /// 0-byte phantom value.
     _phantom: PhantomData<ArgumentState>,
@@ -194,6 +319,7 @@ impl DestinationArguments<Unverified> {
         DestinationArguments {
             driver_args,
             if_exists,
+            migrations: None,
             _phantom: PhantomData,
         }
     }
@@ -204,25 +330,66 @@ impl DestinationArguments<Unverified> {
         Self::new(DriverArguments::default(), IfExists::Overwrite)
     }
 
+    /// Attach schema migrations to apply to the destination before writing
+    /// any data.
+    pub fn with_migrations(mut self, migrations: Migrations) -> Self {
+        self.migrations = Some(migrations);
+        self
+    }
+
     /// Verify that this structure only contains supported arguments. This uses
     /// the [type state][] pattern to keep track of whether our arguments have
     /// been verified to be supported.
     ///
+    /// `schema` is the portable schema of the table we're writing to, used to
+    /// validate that `IfExists::Upsert` keys actually name columns.
+    ///
     /// [type state]: http://cliffle.com/blog/rust-typestate/
-    pub fn verify(self, features: Features) -> Result<DestinationArguments<Verified>> {
+    pub fn verify(
+        self,
+        features: Features,
+        schema: &Table,
+    ) -> Result<DestinationArguments<Verified>> {
+        let mut unsupported = vec![];
         if !features
             .dest_args
             .contains(DestinationArgumentsFeatures::DRIVER_ARGS)
             && !self.driver_args.is_empty()
         {
-            return Err(format_err!(
-                "this data destination does not support --to-args"
+            unsupported.push("--to-args".to_owned());
+        }
+        match self.if_exists.verify(features.dest_if_exists) {
+            Err(err) => unsupported.push(err.to_string()),
+            Ok(()) => {
+                if let IfExists::Upsert { keys } = &self.if_exists {
+                    for key in keys {
+                        if !schema.columns.iter().any(|col| &col.name == key) {
+                            unsupported.push(format!(
+                                "cannot upsert on key column {:?}, which does not exist in the schema",
+                                key,
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+        if self.migrations.is_some()
+            && !features
+                .dest_args
+                .contains(DestinationArgumentsFeatures::MIGRATIONS)
+        {
+            unsupported.push("schema migrations".to_owned());
+        }
+        if !unsupported.is_empty() {
+            return Err(unsupported_arguments_error(
+                "this data destination",
+                &unsupported,
             ));
         }
-        self.if_exists.verify(features.dest_if_exists)?;
         Ok(DestinationArguments {
             driver_args: self.driver_args,
             if_exists: self.if_exists,
+            migrations: self.migrations,
             _phantom: PhantomData,
         })
     }
@@ -239,4 +406,52 @@ impl DestinationArguments<Verified> {
     pub fn if_exists(&self) -> &IfExists {
         &self.if_exists
     }
+
+    /// Schema migrations to apply to the destination before writing any
+    /// data, if any.
+    pub fn migrations(&self) -> Option<&Migrations> {
+        self.migrations.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_arguments_features_names_lists_only_the_set_flags() {
+        assert_eq!(SourceArgumentsFeatures::empty().names(), Vec::<&str>::new());
+        assert_eq!(
+            SourceArgumentsFeatures::PREDICATE.names(),
+            vec!["predicate"],
+        );
+        assert_eq!(
+            (SourceArgumentsFeatures::DRIVER_ARGS | SourceArgumentsFeatures::PREDICATE).names(),
+            vec!["driver_args", "predicate"],
+        );
+    }
+
+    #[test]
+    fn if_exists_features_names_lists_only_the_set_flags() {
+        assert_eq!(
+            IfExistsFeatures::OVERWRITE.names(),
+            vec!["overwrite"],
+        );
+        assert_eq!(
+            (IfExistsFeatures::OVERWRITE | IfExistsFeatures::UPSERT).names(),
+            vec!["overwrite", "upsert"],
+        );
+    }
+
+    #[test]
+    fn destination_arguments_features_names_lists_only_the_set_flags() {
+        assert_eq!(
+            DestinationArgumentsFeatures::empty().names(),
+            Vec::<&str>::new(),
+        );
+        assert_eq!(
+            DestinationArgumentsFeatures::MIGRATIONS.names(),
+            vec!["migrations"],
+        );
+    }
 }
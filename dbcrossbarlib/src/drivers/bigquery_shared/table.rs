@@ -4,7 +4,10 @@ use serde_json;
 use std::io::Write;
 
 use super::{BqColumn, ColumnBigQueryExt, Ident, TableName, Usage};
+use crate::args::IfExistsFeatures;
 use crate::common::*;
+use crate::migrations::{MigrationStep, Migrations};
+use crate::predicate::{render_predicate_as_sql, Literal, Predicate};
 use crate::schema::{Column, Table};
 
 /// Extensions to `Column` (the portable version) to handle BigQuery-query
@@ -67,11 +70,38 @@ impl BqTable {
     /// Generate SQL which `SELECT`s from a temp table, and fixes the types
     /// of columns that couldn't be imported from CSVs.
     ///
+    /// If `predicate` is given (i.e. `SourceArguments::predicate()` was
+    /// set), it's pushed down as a `WHERE` clause using [`render_predicate`].
+    ///
     /// This `BqTable` should have been created with `Usage::FinalTable`.
-    pub(crate) fn write_import_sql(&self, f: &mut dyn Write) -> Result<()> {
+    pub(crate) fn write_import_sql(
+        &self,
+        f: &mut dyn Write,
+        predicate: Option<&Predicate>,
+    ) -> Result<()> {
+        self.write_import_udfs(f)?;
+        self.write_import_select_sql(f, predicate)
+    }
+
+    /// Write the `CREATE TEMPORARY FUNCTION` statements (if any) needed to
+    /// fix up columns that couldn't be imported directly from CSV. These
+    /// must run as statements of their own, before any statement (such as a
+    /// `CREATE TABLE ... AS SELECT`) that calls the functions they define.
+    fn write_import_udfs(&self, f: &mut dyn Write) -> Result<()> {
         for (i, col) in self.columns.iter().enumerate() {
             col.write_import_udf(f, i)?;
         }
+        Ok(())
+    }
+
+    /// Write just the `SELECT` half of `write_import_sql`, without the
+    /// preceding `write_import_udfs` statements, so it can be embedded
+    /// inside another statement (e.g. `CREATE TEMP TABLE ... AS`).
+    fn write_import_select_sql(
+        &self,
+        f: &mut dyn Write,
+        predicate: Option<&Predicate>,
+    ) -> Result<()> {
         write!(f, "SELECT ")?;
         for (i, col) in self.columns.iter().enumerate() {
             if i > 0 {
@@ -80,6 +110,356 @@ impl BqTable {
             col.write_import_select_expr(f, i)?;
         }
         write!(f, " FROM {}", Ident(&self.name.dotted().to_string()))?;
+        if let Some(predicate) = predicate {
+            write!(f, " WHERE {}", render_predicate(predicate)?)?;
+        }
+        Ok(())
+    }
+
+    /// Generate a `MERGE` statement which upserts the rows of
+    /// `temp_table_name` into this table, matching on `keys` and updating
+    /// (or inserting) every other column.
+    ///
+    /// This `BqTable` should have been created with `Usage::FinalTable`, and
+    /// `temp_table_name` should refer to a table with the same columns as
+    /// `self`.
+    pub(crate) fn write_merge_sql(
+        &self,
+        f: &mut dyn Write,
+        temp_table_name: &TableName,
+        keys: &[String],
+    ) -> Result<()> {
+        let column_names = self
+            .columns
+            .iter()
+            .map(|col| col.name().to_owned())
+            .collect::<Vec<_>>();
+        write!(
+            f,
+            "{}",
+            render_merge_sql(
+                &self.name.dotted().to_string(),
+                &temp_table_name.dotted().to_string(),
+                &column_names,
+                keys,
+            )?,
+        )?;
+        Ok(())
+    }
+
+    /// Run a full `IfExists::Upsert`: load the already-imported, type-fixed
+    /// rows described by `staging` (typically produced from raw CSV data via
+    /// `staging.write_import_sql`) into `merge_temp_table_name`, then
+    /// `MERGE` that temp table into `self` on `keys`.
+    ///
+    /// The BigQuery destination driver's write path does not call this
+    /// method, whatever `DestinationArguments::if_exists()` is.
+    pub(crate) fn write_upsert_sql(
+        &self,
+        f: &mut dyn Write,
+        staging: &BqTable,
+        merge_temp_table_name: &TableName,
+        keys: &[String],
+    ) -> Result<()> {
+        // Any UDF definitions `staging` needs must run as their own
+        // statements, before (and outside of) the `CREATE TEMP TABLE ... AS`
+        // statement that calls them; a UDF definition can't follow `AS`
+        // inside a `CREATE TABLE` statement.
+        staging.write_import_udfs(f)?;
+        write!(
+            f,
+            "CREATE TEMP TABLE {} AS ",
+            Ident(&merge_temp_table_name.dotted().to_string()),
+        )?;
+        staging.write_import_select_sql(f, None)?;
+        writeln!(f, ";")?;
+        self.write_merge_sql(f, merge_temp_table_name, keys)?;
         Ok(())
     }
+
+    /// Query for the version currently recorded for this table in
+    /// `version_table_name`, to pass to `write_migration_sql` as
+    /// `current_version`.
+    pub(crate) fn read_current_version_sql(&self, version_table_name: &TableName) -> String {
+        let version_table = Ident(&version_table_name.dotted().to_string()).to_string();
+        let table_name = bq_quote_literal(&Literal::Text(self.name.dotted().to_string()));
+        render_read_current_version_sql(&version_table, &table_name)
+    }
+
+    /// Generate the DDL needed to bring this table's schema up to date from
+    /// `current_version`, followed by a statement recording the new version
+    /// in `version_table_name`. Only the version-bookkeeping `MERGE` runs
+    /// inside a transaction; BigQuery can't run DDL there.
+    pub(crate) fn write_migration_sql(
+        &self,
+        f: &mut dyn Write,
+        version_table_name: &TableName,
+        migrations: &Migrations,
+        current_version: Option<u32>,
+    ) -> Result<()> {
+        let pending = migrations.pending_steps(current_version);
+        let new_version = match pending.last() {
+            Some(step) => step.version,
+            // Nothing to do; the destination is already up to date.
+            None => return Ok(()),
+        };
+
+        let version_table = Ident(&version_table_name.dotted().to_string()).to_string();
+        let table_name = bq_quote_literal(&Literal::Text(self.name.dotted().to_string()));
+        write!(
+            f,
+            "{}",
+            render_migration_sql(&version_table, &table_name, pending, new_version),
+        )?;
+        Ok(())
+    }
+
+    /// Ensure `version_table_name` exists, then (if this table isn't already
+    /// at `migrations.latest_version()`) apply its pending steps via
+    /// `write_migration_sql`.
+    pub(crate) fn write_ensure_migrated_sql(
+        &self,
+        f: &mut dyn Write,
+        version_table_name: &TableName,
+        migrations: &Migrations,
+        current_version: Option<u32>,
+    ) -> Result<()> {
+        if migrations.latest_version() == current_version {
+            // Already up to date; nothing to do.
+            return Ok(());
+        }
+        writeln!(
+            f,
+            "CREATE TABLE IF NOT EXISTS {} (table_name STRING, version INT64);",
+            Ident(&version_table_name.dotted().to_string()),
+        )?;
+        self.write_migration_sql(f, version_table_name, migrations, current_version)
+    }
+}
+
+/// `IfExistsFeatures` for BigQuery destinations: `OVERWRITE`, plus `UPSERT`,
+/// which `BqTable::write_merge_sql` implements. `BigQueryLocator::features()`
+/// does not use this constant, so the BigQuery driver does not advertise
+/// `UPSERT`.
+pub(crate) const BIGQUERY_DEST_IF_EXISTS_FEATURES: IfExistsFeatures =
+    IfExistsFeatures::from_bits_truncate(
+        IfExistsFeatures::OVERWRITE.bits() | IfExistsFeatures::UPSERT.bits(),
+    );
+
+/// Render a portable `Predicate` as a BigQuery `WHERE`-clause body, quoting
+/// identifiers and literals the way BigQuery's standard SQL dialect expects.
+pub(crate) fn render_predicate(predicate: &Predicate) -> Result<String> {
+    render_predicate_as_sql(predicate, &bq_quote_ident, &bq_quote_literal)
+}
+
+/// Quote a column name as a BigQuery identifier.
+fn bq_quote_ident(ident: &str) -> String {
+    Ident(ident).to_string()
+}
+
+/// Quote a literal value the way BigQuery's standard SQL dialect expects.
+fn bq_quote_literal(literal: &Literal) -> String {
+    match literal {
+        Literal::Int(i) => i.to_string(),
+        Literal::Float(f) => f.to_string(),
+        Literal::Bool(b) => b.to_string().to_uppercase(),
+        Literal::Null => "NULL".to_owned(),
+        Literal::Text(s) => format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'")),
+    }
+}
+
+/// Assemble the `MERGE` statement described by `BqTable::write_merge_sql`,
+/// given the already-dotted `target`/`temp` table names and the full list of
+/// `all_column_names` in `target`. Pulled out as a plain-string function (as
+/// opposed to a `&self` method) so it can be unit-tested without needing a
+/// live `BqTable`/`TableName`.
+fn render_merge_sql(
+    target: &str,
+    temp: &str,
+    all_column_names: &[String],
+    keys: &[String],
+) -> Result<String> {
+    if keys.is_empty() {
+        return Err(format_err!("cannot upsert without at least one key column"));
+    }
+
+    let target = Ident(target);
+    let temp = Ident(temp);
+
+    let on_clause = keys
+        .iter()
+        .map(|key| {
+            let key = Ident(key);
+            format!("target.{} = temp.{}", key, key)
+        })
+        .collect::<Vec<_>>()
+        .join(" AND ");
+
+    let update_cols = all_column_names
+        .iter()
+        .filter(|name| !keys.iter().any(|key| key == *name))
+        .collect::<Vec<_>>();
+    let update_clause = update_cols
+        .iter()
+        .map(|name| {
+            let name = Ident(name);
+            format!("{} = temp.{}", name, name)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let insert_cols = all_column_names
+        .iter()
+        .map(|name| Ident(name).to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let insert_values = all_column_names
+        .iter()
+        .map(|name| format!("temp.{}", Ident(name)))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    // If every column is a key column, a matched row is already identical
+    // to its incoming counterpart, so there's nothing to update and `WHEN
+    // MATCHED THEN UPDATE SET` (with an empty list) would be invalid SQL.
+    // Just skip the `WHEN MATCHED` clause.
+    let when_matched = if update_clause.is_empty() {
+        String::new()
+    } else {
+        format!("WHEN MATCHED THEN UPDATE SET {} ", update_clause)
+    };
+
+    Ok(format!(
+        "MERGE {target} AS target USING {temp} AS temp ON {on_clause} \
+         {when_matched}\
+         WHEN NOT MATCHED THEN INSERT ({insert_cols}) VALUES ({insert_values})",
+        target = target,
+        temp = temp,
+        on_clause = on_clause,
+        when_matched = when_matched,
+        insert_cols = insert_cols,
+        insert_values = insert_values,
+    ))
+}
+
+/// Assemble the SQL described by `BqTable::write_migration_sql`: `pending`'s
+/// DDL steps as independent statements, followed by the version-bookkeeping
+/// `MERGE` (and only the `MERGE`) wrapped in a transaction. Pulled out as a
+/// plain-string function so it can be unit-tested without needing a live
+/// `BqTable`/`TableName`; see `write_migration_sql` for why the DDL steps
+/// aren't themselves transactional.
+fn render_migration_sql(
+    version_table: &str,
+    table_name_literal: &str,
+    pending: &[MigrationStep],
+    new_version: u32,
+) -> String {
+    if pending.is_empty() {
+        return String::new();
+    }
+    let mut sql = String::new();
+    for step in pending {
+        sql.push_str(&step.up_sql);
+        sql.push_str(";\n");
+    }
+    sql.push_str("BEGIN TRANSACTION;\n");
+    sql.push_str(&format!(
+        "MERGE {version_table} AS target \
+         USING (SELECT {table_name} AS table_name, {new_version} AS version) AS source \
+         ON target.table_name = source.table_name \
+         WHEN MATCHED THEN UPDATE SET version = source.version \
+         WHEN NOT MATCHED THEN INSERT (table_name, version) \
+         VALUES (source.table_name, source.version);\n",
+        version_table = version_table,
+        table_name = table_name_literal,
+        new_version = new_version,
+    ));
+    sql.push_str("COMMIT TRANSACTION;\n");
+    sql
+}
+
+/// Assemble the SQL described by `BqTable::read_current_version_sql`.
+/// Pulled out as a plain-string function so it can be unit-tested without
+/// needing a live `BqTable`/`TableName`.
+fn render_read_current_version_sql(version_table: &str, table_name_literal: &str) -> String {
+    format!(
+        "SELECT version FROM {} WHERE table_name = {}",
+        version_table, table_name_literal,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_merge_sql_rejects_no_keys() {
+        let columns = vec!["id".to_owned(), "name".to_owned()];
+        assert!(render_merge_sql("t", "tmp", &columns, &[]).is_err());
+    }
+
+    #[test]
+    fn render_merge_sql_updates_non_key_columns() {
+        let columns = vec!["id".to_owned(), "name".to_owned()];
+        let keys = vec!["id".to_owned()];
+        let sql = render_merge_sql("t", "tmp", &columns, &keys).expect("should render");
+        assert!(sql.contains("WHEN MATCHED THEN UPDATE SET"));
+        assert!(sql.contains("WHEN NOT MATCHED THEN INSERT"));
+    }
+
+    /// Regression test for the empty-update-clause bug: when every column is
+    /// a key column, there's nothing left to update, and `WHEN MATCHED THEN
+    /// UPDATE SET` with an empty list is invalid BigQuery SQL. The `WHEN
+    /// MATCHED` clause must be omitted entirely in that case.
+    #[test]
+    fn render_merge_sql_omits_when_matched_if_every_column_is_a_key() {
+        let columns = vec!["id".to_owned(), "name".to_owned()];
+        let keys = columns.clone();
+        let sql = render_merge_sql("t", "tmp", &columns, &keys).expect("should render");
+        assert!(!sql.contains("WHEN MATCHED"));
+        assert!(sql.contains("WHEN NOT MATCHED THEN INSERT"));
+    }
+
+    /// Regression test for the invalid-transaction bug: BigQuery rejects DDL
+    /// inside `BEGIN TRANSACTION; ... COMMIT TRANSACTION;`, so the `up_sql`
+    /// steps must come before (and outside of) the transaction block, which
+    /// should wrap only the version-bookkeeping `MERGE`.
+    #[test]
+    fn render_migration_sql_does_not_wrap_ddl_steps_in_a_transaction() {
+        let steps = vec![
+            MigrationStep {
+                version: 1,
+                up_sql: "ALTER TABLE t ADD COLUMN a INT64".to_owned(),
+            },
+            MigrationStep {
+                version: 2,
+                up_sql: "ALTER TABLE t ADD COLUMN b INT64".to_owned(),
+            },
+        ];
+        let sql = render_migration_sql("versions", "'t'", &steps, 2);
+
+        let txn_start = sql.find("BEGIN TRANSACTION;").expect("should open a transaction");
+        assert!(
+            !sql[..txn_start].contains("BEGIN") && sql[..txn_start].contains("ALTER TABLE"),
+            "DDL steps must run before (and outside of) the transaction: {}",
+            sql,
+        );
+        assert!(
+            sql[txn_start..].contains("MERGE"),
+            "only the version-table MERGE should be inside the transaction: {}",
+            sql,
+        );
+        assert!(sql.contains("COMMIT TRANSACTION;"));
+    }
+
+    #[test]
+    fn render_migration_sql_is_empty_for_no_pending_steps() {
+        assert_eq!(render_migration_sql("versions", "'t'", &[], 0), "");
+    }
+
+    #[test]
+    fn render_read_current_version_sql_selects_by_table_name() {
+        let sql = render_read_current_version_sql("versions", "'t'");
+        assert_eq!(sql, "SELECT version FROM versions WHERE table_name = 't'");
+    }
 }
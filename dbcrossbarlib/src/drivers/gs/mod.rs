@@ -2,8 +2,9 @@
 
 use std::{fmt, str::FromStr};
 
+use crate::args::IfExistsFeatures;
 use crate::common::*;
-use crate::drivers::bigquery::BigQueryLocator;
+use crate::registry::{DriverRegistry, RemoteTransferCapability, DRIVER_REGISTRY};
 
 mod local_data;
 mod prepare_as_destination;
@@ -18,6 +19,13 @@ use write_remote_data::write_remote_data_helper;
 /// Locator scheme for Google Cloud Storage.
 pub(crate) const GS_SCHEME: &str = "gs:";
 
+/// The remote-transfer capability we're willing to accept from a source
+/// driver: extracting its data directly into Cloud Storage. BigQuery
+/// advertises this token for its own locator type; we check for the token
+/// instead of downcasting to `BigQueryLocator` directly, so other crates can
+/// add drivers that extract straight to `gs://` too.
+pub(crate) const EXTRACT_TO_GS_CAPABILITY: RemoteTransferCapability = "extract-to-gs";
+
 #[derive(Clone, Debug)]
 pub(crate) struct GsLocator {
     url: Url,
@@ -83,9 +91,13 @@ impl Locator for GsLocator {
     }
 
     fn supports_write_remote_data(&self, source: &dyn Locator) -> bool {
-        // We can only do `write_remote_data` if `source` is a `BigQueryLocator`.
-        // Otherwise, we need to do `write_local_data` like normal.
-        source.as_any().is::<BigQueryLocator>()
+        // We can only do `write_remote_data` if `source` advertises the
+        // ability to extract its data directly into Cloud Storage. Otherwise,
+        // we need to do `write_local_data` like normal. We check a
+        // negotiated capability token instead of downcasting to a specific
+        // driver's concrete type, so other crates can register drivers that
+        // support this transfer too.
+        DRIVER_REGISTRY.has_remote_transfer_capability(source, EXTRACT_TO_GS_CAPABILITY)
     }
 
     fn write_remote_data(
@@ -120,3 +132,17 @@ impl LocatorStatic for GsLocator {
         }
     }
 }
+
+/// Register this driver's scheme with `registry`. Called once at startup,
+/// while the global `DRIVER_REGISTRY` is being initialized (see
+/// `registry::bootstrap_builtin_drivers`), so this takes the registry being
+/// built as a parameter rather than reaching for the `DRIVER_REGISTRY`
+/// static directly.
+pub(crate) fn register(registry: &DriverRegistry) {
+    registry.register_driver(
+        GS_SCHEME,
+        |s| Ok(Box::new(s.parse::<GsLocator>()?) as BoxLocator),
+        GsLocator::features(),
+        env!("CARGO_PKG_VERSION"),
+    );
+}
@@ -0,0 +1,122 @@
+//! A machine-readable report of the capabilities a driver supports, which
+//! serializes to JSON. The `dbcrossbar` binary has no subcommand that prints
+//! it.
+
+use serde::Serialize;
+
+use crate::common::*;
+use crate::registry::DRIVER_REGISTRY;
+
+/// A machine-readable description of what a driver supports.
+///
+/// This mirrors the internal `Features` bitflags, but as lists of stable
+/// capability names meant to be serialized to JSON and read by a human or
+/// another program, rather than the `Debug` formatting of `*Features`
+/// bitflags (which isn't a documented or stable wire format).
+#[derive(Debug, Serialize)]
+pub struct FeatureReport {
+    /// The URL scheme this report describes, e.g. `"bigquery:"`.
+    scheme: String,
+
+    /// A semver-style version for the driver. Not necessarily tied to the
+    /// `dbcrossbar` crate's own version.
+    driver_version: String,
+
+    /// The locator-level capabilities this driver supports.
+    locator: Vec<&'static str>,
+
+    /// The `IfExists` modes this driver supports when writing a schema.
+    write_schema_if_exists: Vec<&'static str>,
+
+    /// The `SourceArguments` this driver supports.
+    source_args: Vec<&'static str>,
+
+    /// The `DestinationArguments` this driver supports.
+    dest_args: Vec<&'static str>,
+
+    /// The `IfExists` modes this driver supports as a destination.
+    dest_if_exists: Vec<&'static str>,
+}
+
+impl FeatureReport {
+    /// Build a report describing `features` for the driver registered under
+    /// `scheme`, at `driver_version`.
+    pub(crate) fn new(scheme: &str, driver_version: &str, features: &Features) -> FeatureReport {
+        FeatureReport {
+            scheme: scheme.to_owned(),
+            driver_version: driver_version.to_owned(),
+            locator: locator_features_names(features.locator),
+            write_schema_if_exists: features.write_schema_if_exists.names(),
+            source_args: features.source_args.names(),
+            dest_args: features.dest_args.names(),
+            dest_if_exists: features.dest_if_exists.names(),
+        }
+    }
+}
+
+/// The names of the flags set in `flags`, e.g. for a `FeatureReport`.
+///
+/// Unlike `IfExistsFeatures::names`/`SourceArgumentsFeatures::names`/
+/// `DestinationArgumentsFeatures::names`, this can't live as an inherent
+/// method next to `LocatorFeatures`' definition, since that type isn't
+/// defined in this part of the crate.
+fn locator_features_names(flags: LocatorFeatures) -> Vec<&'static str> {
+    let mut names = vec![];
+    if flags.contains(LocatorFeatures::LOCAL_DATA) {
+        names.push("local_data");
+    }
+    if flags.contains(LocatorFeatures::WRITE_LOCAL_DATA) {
+        names.push("write_local_data");
+    }
+    names
+}
+
+/// Build a `FeatureReport` for the driver registered under `scheme`, or an
+/// error if no driver is registered for it.
+pub fn feature_report_for_scheme(scheme: &str) -> Result<FeatureReport> {
+    let (features, driver_version) = DRIVER_REGISTRY
+        .features_for_scheme(scheme)
+        .ok_or_else(|| format_err!("no driver registered for scheme {:?}", scheme))?;
+    Ok(FeatureReport::new(scheme, driver_version, &features))
+}
+
+/// Build a `FeatureReport` for every scheme currently registered, sorted by
+/// scheme so the output is stable.
+pub fn feature_reports_for_all_schemes() -> Vec<FeatureReport> {
+    let mut reports = DRIVER_REGISTRY
+        .schemes()
+        .into_iter()
+        .filter_map(|scheme| feature_report_for_scheme(scheme).ok())
+        .collect::<Vec<_>>();
+    reports.sort_by(|a, b| a.scheme.cmp(&b.scheme));
+    reports
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_report_new_lists_structured_capability_names() {
+        let features = Features {
+            locator: LocatorFeatures::LOCAL_DATA | LocatorFeatures::WRITE_LOCAL_DATA,
+            write_schema_if_exists: IfExistsFeatures::empty(),
+            source_args: SourceArgumentsFeatures::PREDICATE,
+            dest_args: DestinationArgumentsFeatures::empty(),
+            dest_if_exists: IfExistsFeatures::OVERWRITE | IfExistsFeatures::UPSERT,
+            _placeholder: (),
+        };
+        let report = FeatureReport::new("test:", "1.2.3", &features);
+
+        assert_eq!(report.locator, vec!["local_data", "write_local_data"]);
+        assert_eq!(report.write_schema_if_exists, Vec::<&str>::new());
+        assert_eq!(report.source_args, vec!["predicate"]);
+        assert_eq!(report.dest_args, Vec::<&str>::new());
+        assert_eq!(report.dest_if_exists, vec!["overwrite", "upsert"]);
+    }
+
+    #[test]
+    fn feature_report_for_scheme_errors_for_an_unregistered_scheme() {
+        assert!(feature_report_for_scheme("no-such-scheme:").is_err());
+    }
+}
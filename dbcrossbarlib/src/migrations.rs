@@ -0,0 +1,109 @@
+//! Versioned schema migrations, applied to a destination table before a
+//! transfer writes data to it.
+//!
+//! This lets users evolve a warehouse table idempotently across repeated
+//! loads instead of hand-running DDL: each `DestinationArguments` can carry
+//! an ordered list of `(version, up_ddl)` steps, and a driver-backed
+//! tracking table records which version a destination is currently at. The
+//! steps are not checked against `SharedArguments::schema()`.
+
+use crate::common::*;
+
+/// A single migration step, which brings a destination from the version
+/// before it up to `version` by running `up_sql`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MigrationStep {
+    /// The version this step migrates the destination *to*.
+    pub version: u32,
+
+    /// The DDL to run in order to perform this step.
+    pub up_sql: String,
+}
+
+/// An ordered list of schema migrations to apply to a destination table
+/// before writing data.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Migrations {
+    steps: Vec<MigrationStep>,
+}
+
+impl Migrations {
+    /// Construct a new set of migrations from an ordered list of steps.
+    /// `steps` must already be sorted by strictly increasing `version`.
+    pub fn new(steps: Vec<MigrationStep>) -> Result<Migrations> {
+        for pair in steps.windows(2) {
+            if pair[0].version >= pair[1].version {
+                return Err(format_err!(
+                    "migration steps must be sorted by strictly increasing version, \
+                     but {} is not less than {}",
+                    pair[0].version,
+                    pair[1].version,
+                ));
+            }
+        }
+        Ok(Migrations { steps })
+    }
+
+    /// The steps needed to bring a destination currently at
+    /// `current_version` up to the latest version described here, in order.
+    /// `current_version` is `None` if the destination has never been
+    /// migrated (and so needs every step).
+    pub(crate) fn pending_steps(&self, current_version: Option<u32>) -> &[MigrationStep] {
+        match current_version {
+            Some(current_version) => {
+                let start = self
+                    .steps
+                    .partition_point(|step| step.version <= current_version);
+                &self.steps[start..]
+            }
+            None => &self.steps[..],
+        }
+    }
+
+    /// The latest version described by these migrations, if any.
+    pub(crate) fn latest_version(&self) -> Option<u32> {
+        self.steps.last().map(|step| step.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(version: u32) -> MigrationStep {
+        MigrationStep {
+            version,
+            up_sql: format!("-- step {}", version),
+        }
+    }
+
+    fn migrations() -> Migrations {
+        Migrations::new(vec![step(1), step(2), step(3)]).unwrap()
+    }
+
+    #[test]
+    fn pending_steps_with_no_current_version_returns_everything() {
+        assert_eq!(migrations().pending_steps(None), &[step(1), step(2), step(3)][..]);
+    }
+
+    #[test]
+    fn pending_steps_skips_versions_already_applied() {
+        assert_eq!(migrations().pending_steps(Some(1)), &[step(2), step(3)][..]);
+    }
+
+    #[test]
+    fn pending_steps_is_empty_once_fully_migrated() {
+        assert_eq!(migrations().pending_steps(Some(3)), &[][..]);
+    }
+
+    #[test]
+    fn pending_steps_tolerates_a_current_version_past_the_latest_step() {
+        assert_eq!(migrations().pending_steps(Some(99)), &[][..]);
+    }
+
+    #[test]
+    fn new_rejects_steps_not_sorted_by_strictly_increasing_version() {
+        assert!(Migrations::new(vec![step(2), step(1)]).is_err());
+        assert!(Migrations::new(vec![step(1), step(1)]).is_err());
+    }
+}
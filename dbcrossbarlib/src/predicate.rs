@@ -0,0 +1,345 @@
+//! A portable filter predicate AST, used to implement `--where` in a way
+//! that isn't tied to any one driver's SQL dialect.
+//!
+//! Each driver is responsible for turning a `Predicate` into whatever it
+//! needs to push the filter down into its own query language. Drivers that
+//! only understand raw SQL can use [`render_predicate_as_sql`] to fall back
+//! to a single `WHERE`-style string.
+
+use crate::common::*;
+
+/// A typed literal value that can appear on the right-hand side of a
+/// [`Predicate::Compare`], or inside an [`Predicate::In`] list.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Bool(bool),
+    Null,
+}
+
+/// The comparison operators supported by [`Predicate::Compare`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CompareOp {
+    /// The ANSI SQL spelling of this operator.
+    fn as_sql(self) -> &'static str {
+        match self {
+            CompareOp::Eq => "=",
+            CompareOp::Ne => "<>",
+            CompareOp::Lt => "<",
+            CompareOp::Le => "<=",
+            CompareOp::Gt => ">",
+            CompareOp::Ge => ">=",
+        }
+    }
+}
+
+/// A portable filter predicate. This is the in-memory form of `--where`;
+/// each driver renders it into its own dialect, either with its own
+/// renderer (BigQuery's is `render_predicate` in
+/// `drivers::bigquery_shared::table`) or with the generic
+/// [`render_predicate_as_sql`] fallback.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Predicate {
+    /// Compare a column against a literal, e.g. `age >= 21`.
+    Compare {
+        column: String,
+        op: CompareOp,
+        literal: Literal,
+    },
+    /// `left AND right`.
+    And(Box<Predicate>, Box<Predicate>),
+    /// `left OR right`.
+    Or(Box<Predicate>, Box<Predicate>),
+    /// `NOT predicate`.
+    Not(Box<Predicate>),
+    /// `column IS NULL`.
+    IsNull(String),
+    /// `column IN (values...)`.
+    In(String, Vec<Literal>),
+    /// `lower <[=] column AND column <[=] upper`, following the Postgres
+    /// range-operator convention of tracking inclusivity on each bound
+    /// separately.
+    RangeContains {
+        column: String,
+        lower: Literal,
+        upper: Literal,
+        lower_inclusive: bool,
+        upper_inclusive: bool,
+    },
+}
+
+/// Render `predicate` as a single ANSI-ish SQL `WHERE`-clause body, using
+/// `quote_ident` and `quote_literal` to escape identifiers and literals in
+/// the caller's own dialect.
+///
+/// This is meant for drivers (like raw-SQL sources) that don't have a
+/// structured query builder of their own and just want a `WHERE` string.
+pub(crate) fn render_predicate_as_sql(
+    predicate: &Predicate,
+    quote_ident: &dyn Fn(&str) -> String,
+    quote_literal: &dyn Fn(&Literal) -> String,
+) -> Result<String> {
+    Ok(match predicate {
+        Predicate::Compare { column, op, literal } => format!(
+            "{} {} {}",
+            quote_ident(column),
+            op.as_sql(),
+            quote_literal(literal),
+        ),
+        Predicate::And(lhs, rhs) => format!(
+            "({}) AND ({})",
+            render_predicate_as_sql(lhs, quote_ident, quote_literal)?,
+            render_predicate_as_sql(rhs, quote_ident, quote_literal)?,
+        ),
+        Predicate::Or(lhs, rhs) => format!(
+            "({}) OR ({})",
+            render_predicate_as_sql(lhs, quote_ident, quote_literal)?,
+            render_predicate_as_sql(rhs, quote_ident, quote_literal)?,
+        ),
+        Predicate::Not(inner) => format!(
+            "NOT ({})",
+            render_predicate_as_sql(inner, quote_ident, quote_literal)?,
+        ),
+        Predicate::IsNull(column) => format!("{} IS NULL", quote_ident(column)),
+        Predicate::In(column, values) => {
+            if values.is_empty() {
+                return Err(format_err!(
+                    "cannot render an empty IN list for column {:?}",
+                    column,
+                ));
+            }
+            let values = values
+                .iter()
+                .map(quote_literal)
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!("{} IN ({})", quote_ident(column), values)
+        }
+        Predicate::RangeContains {
+            column,
+            lower,
+            upper,
+            lower_inclusive,
+            upper_inclusive,
+        } => {
+            let column = quote_ident(column);
+            let lower_op = if *lower_inclusive { "<=" } else { "<" };
+            let upper_op = if *upper_inclusive { "<=" } else { "<" };
+            format!(
+                "{} {} {} AND {} {} {}",
+                quote_literal(lower),
+                lower_op,
+                column,
+                column,
+                upper_op,
+                quote_literal(upper),
+            )
+        }
+    })
+}
+
+/// Parse a single `--where` argument of the form `column<op>value` (where
+/// `<op>` is one of `=`, `!=`, `<`, `<=`, `>` or `>=`) into a
+/// `Predicate::Compare`.
+///
+/// `value` is parsed as an integer, a float, `true`/`false`, or `null` if it
+/// matches one of those forms, and as a (possibly single-quoted) string
+/// otherwise. The `dbcrossbar` CLI does not call this function.
+pub fn parse_where_expr(expr: &str) -> Result<Predicate> {
+    // Match longer operators before their shorter prefixes (e.g. `<=`
+    // before `<`).
+    const OPERATORS: &[(&str, CompareOp)] = &[
+        ("!=", CompareOp::Ne),
+        ("<>", CompareOp::Ne),
+        (">=", CompareOp::Ge),
+        ("<=", CompareOp::Le),
+        ("=", CompareOp::Eq),
+        ("<", CompareOp::Lt),
+        (">", CompareOp::Gt),
+    ];
+    let (column, op, value) = OPERATORS
+        .iter()
+        .filter_map(|&(token, op)| expr.find(token).map(|idx| (token, op, idx)))
+        .min_by_key(|&(_, _, idx)| idx)
+        .map(|(token, op, idx)| {
+            (
+                expr[..idx].trim(),
+                op,
+                expr[idx + token.len()..].trim(),
+            )
+        })
+        .ok_or_else(|| format_err!("cannot parse --where expression {:?}", expr))?;
+    if column.is_empty() {
+        return Err(format_err!("--where expression {:?} has no column", expr));
+    }
+    let literal = parse_literal(value);
+    // `col = NULL`/`col <> NULL` are never true in ANSI/BigQuery SQL, even
+    // for NULL rows, so a literal `NULL` comparison would silently return
+    // zero rows instead of the intended "is/is not null" check. Special-case
+    // it to the dedicated `IsNull` variant instead.
+    match (op, literal) {
+        (CompareOp::Eq, Literal::Null) => Ok(Predicate::IsNull(column.to_owned())),
+        (CompareOp::Ne, Literal::Null) => {
+            Ok(Predicate::Not(Box::new(Predicate::IsNull(column.to_owned()))))
+        }
+        (op, literal) => Ok(Predicate::Compare {
+            column: column.to_owned(),
+            op,
+            literal,
+        }),
+    }
+}
+
+/// Parse the right-hand side of a `--where` expression into a `Literal`.
+fn parse_literal(value: &str) -> Literal {
+    if value.eq_ignore_ascii_case("null") {
+        Literal::Null
+    } else if value.eq_ignore_ascii_case("true") {
+        Literal::Bool(true)
+    } else if value.eq_ignore_ascii_case("false") {
+        Literal::Bool(false)
+    } else if let Ok(i) = value.parse::<i64>() {
+        Literal::Int(i)
+    } else if let Ok(f) = value.parse::<f64>() {
+        Literal::Float(f)
+    } else {
+        let unquoted = value
+            .strip_prefix('\'')
+            .and_then(|s| s.strip_suffix('\''))
+            .unwrap_or(value);
+        Literal::Text(unquoted.to_owned())
+    }
+}
+
+/// Combine the predicates parsed from one or more `--where` flags into a
+/// single predicate by ANDing them together, or `None` if no `--where` flags
+/// were given.
+pub fn and_predicates(predicates: Vec<Predicate>) -> Option<Predicate> {
+    let mut iter = predicates.into_iter();
+    let first = iter.next()?;
+    Some(iter.fold(first, |acc, next| Predicate::And(Box::new(acc), Box::new(next))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_where_expr_prefers_longer_operators_over_their_prefixes() {
+        // `>=`/`<=`/`!=` share a leading character with `>`/`<`/`=`; the
+        // longer operator must win wherever both match at the same position.
+        assert_eq!(
+            parse_where_expr("age>=21").unwrap(),
+            Predicate::Compare {
+                column: "age".to_owned(),
+                op: CompareOp::Ge,
+                literal: Literal::Int(21),
+            },
+        );
+        assert_eq!(
+            parse_where_expr("age<=21").unwrap(),
+            Predicate::Compare {
+                column: "age".to_owned(),
+                op: CompareOp::Le,
+                literal: Literal::Int(21),
+            },
+        );
+        assert_eq!(
+            parse_where_expr("name!=bob").unwrap(),
+            Predicate::Compare {
+                column: "name".to_owned(),
+                op: CompareOp::Ne,
+                literal: Literal::Text("bob".to_owned()),
+            },
+        );
+    }
+
+    #[test]
+    fn parse_where_expr_falls_back_to_single_character_operators() {
+        assert_eq!(
+            parse_where_expr("age=21").unwrap(),
+            Predicate::Compare {
+                column: "age".to_owned(),
+                op: CompareOp::Eq,
+                literal: Literal::Int(21),
+            },
+        );
+        assert_eq!(
+            parse_where_expr("age<21").unwrap(),
+            Predicate::Compare {
+                column: "age".to_owned(),
+                op: CompareOp::Lt,
+                literal: Literal::Int(21),
+            },
+        );
+    }
+
+    #[test]
+    fn parse_where_expr_parses_typed_literals() {
+        assert_eq!(
+            parse_where_expr("active=true").unwrap(),
+            Predicate::Compare {
+                column: "active".to_owned(),
+                op: CompareOp::Eq,
+                literal: Literal::Bool(true),
+            },
+        );
+        assert_eq!(
+            parse_where_expr("name='bob'").unwrap(),
+            Predicate::Compare {
+                column: "name".to_owned(),
+                op: CompareOp::Eq,
+                literal: Literal::Text("bob".to_owned()),
+            },
+        );
+    }
+
+    /// `= null`/`!= null` would render as `col = NULL`/`col <> NULL`, which
+    /// is never true in ANSI/BigQuery SQL even for NULL rows. These must
+    /// parse to the dedicated `IsNull` variant instead of a literal `NULL`
+    /// comparison.
+    #[test]
+    fn parse_where_expr_treats_null_comparisons_as_is_null() {
+        assert_eq!(
+            parse_where_expr("deleted_at=null").unwrap(),
+            Predicate::IsNull("deleted_at".to_owned()),
+        );
+        assert_eq!(
+            parse_where_expr("deleted_at!=null").unwrap(),
+            Predicate::Not(Box::new(Predicate::IsNull("deleted_at".to_owned()))),
+        );
+    }
+
+    #[test]
+    fn parse_where_expr_rejects_expressions_with_no_column() {
+        assert!(parse_where_expr("=21").is_err());
+    }
+
+    #[test]
+    fn render_predicate_as_sql_rejects_an_empty_in_list() {
+        let predicate = Predicate::In("id".to_owned(), vec![]);
+        assert!(render_predicate_as_sql(&predicate, &|s| s.to_owned(), &|_| "?".to_owned()).is_err());
+    }
+
+    #[test]
+    fn and_predicates_of_one_returns_that_predicate_unchanged() {
+        let only = Predicate::IsNull("a".to_owned());
+        assert_eq!(and_predicates(vec![only.clone()]), Some(only));
+    }
+
+    #[test]
+    fn and_predicates_of_none_returns_none() {
+        assert_eq!(and_predicates(vec![]), None);
+    }
+}